@@ -8,7 +8,7 @@ use proptest::{
 use std::{collections::HashMap, sync::Arc};
 
 use crate::{
-    amount::NonNegative,
+    amount::{Amount, NonNegative},
     block,
     fmt::SummaryDebug,
     history_tree::HistoryTree,
@@ -108,6 +108,19 @@ pub struct LedgerState {
     /// For an individual transaction, make the transaction a coinbase
     /// transaction.
     pub(crate) has_coinbase: bool,
+
+    /// Recompute and fix up `header.merkle_root` from the final block
+    /// transactions, after the per-block transaction fixup in
+    /// [`Block::partial_chain_strategy`].
+    pub(crate) generate_valid_merkle_root: bool,
+
+    /// Inject exactly one deliberate consensus violation into a generated
+    /// chain, for negative tests. See [`FaultKind`].
+    pub(crate) invalid_block_faults: Option<FaultKind>,
+
+    /// Rewrite a fraction of later shielded spends to reference real anchors
+    /// and nullifiers created by earlier blocks in the chain.
+    pub(crate) generate_valid_shielded_spends: bool,
 }
 
 /// Overrides for arbitrary [`LedgerState`]s.
@@ -135,6 +148,62 @@ pub struct LedgerStateOverride {
     /// Every block has exactly one coinbase transaction.
     /// Transactions are always coinbase transactions.
     pub always_has_coinbase: bool,
+
+    /// Recompute `header.merkle_root` from the final block transactions, so
+    /// generated blocks pass full header/transaction consistency checks.
+    pub generate_valid_merkle_root: bool,
+
+    /// Inject exactly one deliberate consensus violation into a generated
+    /// chain, for negative tests.
+    ///
+    /// If set, [`Block::partial_invalid_chain_strategy`] tampers with one
+    /// randomly chosen block so the chain is guaranteed to fail validation for
+    /// the given, introspectable reason.
+    pub invalid_block_faults: Option<FaultKind>,
+
+    /// Rewrite a fraction of later shielded spends to reference real anchors
+    /// and nullifiers created by earlier blocks, so generated chains exercise
+    /// cross-block anchor validity and nullifier double-spend detection.
+    pub generate_valid_shielded_spends: bool,
+}
+
+/// A deliberate consensus violation injected into a generated chain.
+///
+/// Used by [`Block::partial_invalid_chain_strategy`] so checkpoint and
+/// contextual-verification tests can property-test that the validator
+/// *rejects* bad blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// The block's `previous_block_hash` does not match its parent.
+    BadPrevHash,
+
+    /// The block's height is not one more than its parent's height.
+    NonMonotonicHeight,
+
+    /// A transparent input is spent twice in the chain.
+    DoubleSpend,
+
+    /// The block has no coinbase transaction.
+    MissingCoinbase,
+
+    /// The block's transactions spend more than the value pool holds.
+    OverspentValuePool,
+
+    /// The block's `commitment_bytes` do not match the chain history.
+    TamperedCommitment,
+}
+
+/// The block and fault injected by [`Block::partial_invalid_chain_strategy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InjectedFault {
+    /// The index of the tampered block in the returned chain.
+    pub block_index: usize,
+
+    /// The height of the tampered block.
+    pub height: Height,
+
+    /// The kind of violation that was injected.
+    pub fault: FaultKind,
 }
 
 impl LedgerState {
@@ -153,6 +222,9 @@ impl LedgerState {
             transaction_version_override: None,
             transaction_has_valid_network_upgrade: false,
             always_has_coinbase: false,
+            generate_valid_merkle_root: false,
+            invalid_block_faults: None,
+            generate_valid_shielded_spends: false,
         })
     }
 
@@ -172,6 +244,9 @@ impl LedgerState {
             transaction_version_override: transaction_version_override.into(),
             transaction_has_valid_network_upgrade,
             always_has_coinbase: false,
+            generate_valid_merkle_root: false,
+            invalid_block_faults: None,
+            generate_valid_shielded_spends: false,
         })
     }
 
@@ -191,6 +266,9 @@ impl LedgerState {
             transaction_version_override: transaction_version_override.into(),
             transaction_has_valid_network_upgrade,
             always_has_coinbase: true,
+            generate_valid_merkle_root: false,
+            invalid_block_faults: None,
+            generate_valid_shielded_spends: false,
         })
     }
 
@@ -214,6 +292,9 @@ impl LedgerState {
             transaction_version_override: transaction_version_override.into(),
             transaction_has_valid_network_upgrade,
             always_has_coinbase: true,
+            generate_valid_merkle_root: false,
+            invalid_block_faults: None,
+            generate_valid_shielded_spends: false,
         })
     }
 
@@ -234,6 +315,9 @@ impl LedgerState {
             transaction_version_override: transaction_version_override.into(),
             transaction_has_valid_network_upgrade,
             always_has_coinbase: true,
+            generate_valid_merkle_root: false,
+            invalid_block_faults: None,
+            generate_valid_shielded_spends: false,
         })
     }
 
@@ -281,6 +365,9 @@ impl Default for LedgerState {
             transaction_has_valid_network_upgrade: default_override
                 .transaction_has_valid_network_upgrade,
             has_coinbase: default_override.always_has_coinbase,
+            generate_valid_merkle_root: default_override.generate_valid_merkle_root,
+            invalid_block_faults: default_override.invalid_block_faults,
+            generate_valid_shielded_spends: default_override.generate_valid_shielded_spends,
         }
     }
 }
@@ -304,6 +391,9 @@ impl Default for LedgerStateOverride {
             transaction_version_override: None,
             transaction_has_valid_network_upgrade: false,
             always_has_coinbase: true,
+            generate_valid_merkle_root: false,
+            invalid_block_faults: None,
+            generate_valid_shielded_spends: false,
         }
     }
 }
@@ -335,6 +425,10 @@ impl Arbitrary for LedgerState {
                             .transaction_has_valid_network_upgrade
                             || transaction_has_valid_network_upgrade,
                         has_coinbase: ledger_override.always_has_coinbase || has_coinbase,
+                        generate_valid_merkle_root: ledger_override.generate_valid_merkle_root,
+                        invalid_block_faults: ledger_override.invalid_block_faults,
+                        generate_valid_shielded_spends: ledger_override
+                            .generate_valid_shielded_spends,
                     }
                 },
             )
@@ -353,14 +447,13 @@ impl Arbitrary for Block {
                 Transaction::vec_strategy(ledger_state, transaction_count)
             });
 
-        // TODO: if needed, fixup:
-        // - history and authorizing data commitments
-        // - the transaction merkle root
+        let network = ledger_state.network;
 
         (Header::arbitrary_with(ledger_state), transactions_strategy)
-            .prop_map(move |(header, transactions)| Self {
-                header,
-                transactions,
+            .prop_map(move |(header, transactions)| {
+                // assemble a block whose header is consistent with its body,
+                // so generated blocks can feed header+body validation paths
+                assemble_block(network, header, transactions)
             })
             .boxed()
     }
@@ -413,150 +506,1019 @@ impl Block {
             current.height.0 += 1;
         }
 
+        let network = current.network;
+        let generate_valid_merkle_root = current.generate_valid_merkle_root;
+        let generate_valid_shielded_spends = current.generate_valid_shielded_spends;
+
         // after the vec strategy generates blocks, fixup invalid parts of the blocks
-        vec.prop_map(move |mut vec| {
-            let mut previous_block_hash = None;
-            let mut utxos = HashMap::new();
-            let mut chain_value_pools = ValueBalance::zero();
-            let mut sapling_tree = sapling::tree::NoteCommitmentTree::default();
-            let mut orchard_tree = orchard::tree::NoteCommitmentTree::default();
-            // The history tree usually takes care of "creating itself". But this
-            // only works when blocks are pushed into it starting from genesis
-            // (or at least pre-Heartwood, where the tree is not required).
-            // However, this strategy can generate blocks from an arbitrary height,
-            // so we must wait for the first block to create the history tree from it.
-            // This is why `Option` is used here.
-            let mut history_tree: Option<HistoryTree> = None;
-
-            for (height, block) in vec.iter_mut() {
-                // fixup the previous block hash
-                if let Some(previous_block_hash) = previous_block_hash {
-                    block.header.previous_block_hash = previous_block_hash;
-                }
+        vec.prop_map(move |vec| {
+            SummaryDebug(fixup_generated_chain(
+                vec,
+                network,
+                None,
+                check_transparent_coinbase_spend,
+                generate_valid_commitments,
+                generate_valid_merkle_root,
+                generate_valid_shielded_spends,
+            ))
+        })
+        .boxed()
+    }
 
-                let mut new_transactions = Vec::new();
-                for (tx_index_in_block, transaction) in block.transactions.drain(..).enumerate() {
-                    if let Some(transaction) = fix_generated_transaction(
-                        (*transaction).clone(),
-                        tx_index_in_block,
-                        *height,
-                        &mut chain_value_pools,
-                        &mut utxos,
-                        check_transparent_coinbase_spend,
-                    ) {
-                        // The FinalizedState does not update the note commitment trees with the genesis block,
-                        // because it doesn't need to (the trees are not used at that point) and updating them
-                        // would be awkward since the genesis block is handled separatedly there.
-                        // This forces us to skip the genesis block here too in order to able to use
-                        // this to test the finalized state.
-                        if generate_valid_commitments && *height != Height(0) {
-                            for sapling_note_commitment in transaction.sapling_note_commitments() {
-                                sapling_tree.append(*sapling_note_commitment).unwrap();
-                            }
-                            for orchard_note_commitment in transaction.orchard_note_commitments() {
-                                orchard_tree.append(*orchard_note_commitment).unwrap();
+    /// Returns a strategy for creating a tree of blocks: a main chain plus one
+    /// or more side branches that split off at an arbitrary height.
+    ///
+    /// The returned value is the main chain followed by each side branch, so
+    /// zebra-state reorg and best-chain-selection tests can drive realistic
+    /// competing chains instead of hand-built ones.
+    ///
+    /// `count` is the length of the main chain. Each side branch forks from a
+    /// randomly chosen main-chain block and continues for a random number of
+    /// blocks, wiring its first block's `previous_block_hash` to the fork point.
+    /// Like [`Block::partial_chain_strategy`], each branch maintains independent
+    /// `utxos`/`chain_value_pools`/note-commitment/history-tree state, so every
+    /// branch is internally consistent.
+    ///
+    /// See [`Block::partial_chain_strategy`] for the meaning of
+    /// `check_transparent_coinbase_spend` and `generate_valid_commitments`.
+    pub fn fork_tree_strategy<F, T, E>(
+        current: LedgerState,
+        count: usize,
+        branch_count: usize,
+        check_transparent_coinbase_spend: F,
+        generate_valid_commitments: bool,
+    ) -> BoxedStrategy<SummaryDebug<Vec<Vec<Arc<Self>>>>>
+    where
+        F: Fn(
+                transparent::OutPoint,
+                transparent::CoinbaseSpendRestriction,
+                transparent::OrderedUtxo,
+            ) -> Result<T, E>
+            + Copy
+            + 'static,
+    {
+        let network = current.network;
+        let generate_valid_merkle_root = current.generate_valid_merkle_root;
+        let generate_valid_shielded_spends = current.generate_valid_shielded_spends;
+
+        // the raw (unfixed) main chain blocks
+        let mut main_blocks = Vec::with_capacity(count);
+        let mut main_state = current;
+        for _ in 0..count {
+            main_blocks.push((Just(main_state.height), Block::arbitrary_with(main_state)));
+            main_state.height.0 += 1;
+        }
+
+        // the raw (unfixed) blocks for each side branch, generated at the chain's
+        // start height; their heights are re-based onto the fork point below
+        let mut branch_blocks = Vec::with_capacity(branch_count);
+        for _ in 0..branch_count {
+            let mut branch = Vec::with_capacity(MAX_PARTIAL_CHAIN_BLOCKS);
+            let mut branch_state = current;
+            for _ in 0..MAX_PARTIAL_CHAIN_BLOCKS {
+                branch.push((Just(branch_state.height), Block::arbitrary_with(branch_state)));
+                branch_state.height.0 += 1;
+            }
+            branch_blocks.push(branch);
+        }
+
+        // a fork point and length for each side branch
+        let branch_params = proptest::collection::vec(
+            (1..count.max(2), 1..=MAX_PARTIAL_CHAIN_BLOCKS),
+            branch_count,
+        );
+
+        (main_blocks, branch_blocks, branch_params)
+            .prop_map(move |(main_blocks, branch_blocks, branch_params)| {
+                let main_chain = fixup_generated_chain(
+                    main_blocks,
+                    network,
+                    None,
+                    check_transparent_coinbase_spend,
+                    generate_valid_commitments,
+                    generate_valid_merkle_root,
+                    generate_valid_shielded_spends,
+                );
+
+                let mut chains = vec![main_chain.clone()];
+
+                for (branch, (fork_index, branch_len)) in branch_blocks.into_iter().zip(branch_params)
+                {
+                    // clamp the fork point to a block that actually exists
+                    let fork_index = fork_index.min(main_chain.len() - 1);
+                    let fork_block = &main_chain[fork_index];
+                    let fork_height = fork_block
+                        .coinbase_height()
+                        .expect("generated blocks have coinbase heights");
+
+                    // re-base the branch's heights onto the fork point, and keep
+                    // only `branch_len` blocks
+                    let branch: Vec<_> = branch
+                        .into_iter()
+                        .take(branch_len)
+                        .enumerate()
+                        .map(|(i, (_height, mut block))| {
+                            let height = Height(fork_height.0 + 1 + i as u32);
+                            // the coinbase height must follow the fork point too
+                            if let Some(coinbase) = block.transactions.first_mut() {
+                                for input in Arc::make_mut(coinbase).inputs_mut() {
+                                    if let transparent::Input::Coinbase {
+                                        height: coinbase_height,
+                                        ..
+                                    } = input
+                                    {
+                                        *coinbase_height = height;
+                                    }
+                                }
                             }
-                        }
-                        new_transactions.push(Arc::new(transaction));
+                            (height, block)
+                        })
+                        .collect();
+
+                    chains.push(fixup_generated_chain(
+                        branch,
+                        network,
+                        Some(fork_block.hash()),
+                        check_transparent_coinbase_spend,
+                        generate_valid_commitments,
+                        generate_valid_merkle_root,
+                        generate_valid_shielded_spends,
+                    ));
+                }
+
+                SummaryDebug(chains)
+            })
+            .boxed()
+    }
+
+    /// Returns a strategy for creating a chain that is guaranteed to fail
+    /// validation for a known, introspectable reason.
+    ///
+    /// Generates a chain like [`Block::partial_chain_strategy`], then injects
+    /// exactly one of `current.invalid_block_faults` into a randomly chosen
+    /// block after the normal fixup pass. The returned value carries both the
+    /// chain and an [`InjectedFault`] describing which block was tampered with
+    /// and how, so negative tests can assert the validator rejects the chain
+    /// for that specific reason.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `current.invalid_block_faults` is `None`.
+    pub fn partial_invalid_chain_strategy<F, T, E>(
+        current: LedgerState,
+        count: usize,
+        check_transparent_coinbase_spend: F,
+        generate_valid_commitments: bool,
+    ) -> BoxedStrategy<SummaryDebug<(Vec<Arc<Self>>, InjectedFault)>>
+    where
+        F: Fn(
+                transparent::OutPoint,
+                transparent::CoinbaseSpendRestriction,
+                transparent::OrderedUtxo,
+            ) -> Result<T, E>
+            + Copy
+            + 'static,
+    {
+        let fault = current
+            .invalid_block_faults
+            .expect("partial_invalid_chain_strategy requires invalid_block_faults to be set");
+
+        (
+            Block::partial_chain_strategy(
+                current,
+                count,
+                check_transparent_coinbase_spend,
+                generate_valid_commitments,
+            ),
+            // the index of the block to tamper with, chosen at random
+            0..count,
+        )
+            .prop_map(move |(chain, fault_index)| {
+                let mut chain = chain.0;
+                let fault_index = fault_index.min(chain.len().saturating_sub(1));
+
+                let injected = inject_block_fault(&mut chain, fault_index, fault);
+
+                SummaryDebug((chain, injected))
+            })
+            .boxed()
+    }
+}
+
+/// Inject `fault` into the block at `index` in `chain`, returning a description
+/// of the violation.
+///
+/// Each variant mutates the block in a distinct, introspectable way so that
+/// exactly one consensus rule is violated.
+fn inject_block_fault(chain: &mut [Arc<Block>], index: usize, fault: FaultKind) -> InjectedFault {
+    let height = chain[index].coinbase_height().unwrap_or(Height(0));
+    let block = Arc::make_mut(&mut chain[index]);
+
+    match fault {
+        FaultKind::BadPrevHash => {
+            // chain onto a hash that is not the parent
+            block.header.previous_block_hash = block::Hash([0x0f; 32]);
+        }
+        FaultKind::NonMonotonicHeight => {
+            // rewrite the coinbase height so it no longer follows the parent
+            // (a block's height must be its parent's height plus one), while
+            // leaving `previous_block_hash` pointing at the real parent so the
+            // height rule is the only thing violated
+            // Offset the height up by one so it never equals the parent's
+            // height plus one. Offsetting up (rather than down) keeps this a
+            // real violation at height 0, where a downward offset would
+            // saturate back to the block's own height and inject nothing.
+            let non_monotonic_height = Height(height.0.saturating_add(1));
+            if let Some(coinbase) = block.transactions.first_mut() {
+                let coinbase = Arc::make_mut(coinbase);
+                for input in coinbase.inputs_mut() {
+                    if let transparent::Input::Coinbase {
+                        height: coinbase_height,
+                        ..
+                    } = input
+                    {
+                        *coinbase_height = non_monotonic_height;
                     }
                 }
+                // keep the merkle root consistent with the re-coinbased body, so
+                // the block is rejected for its height and nothing else
+                block.header.merkle_root =
+                    block.transactions.iter().map(AsRef::as_ref).collect();
+            }
+        }
+        FaultKind::MissingCoinbase => {
+            // drop the coinbase transaction, which must be first
+            if !block.transactions.is_empty() {
+                block.transactions.remove(0);
+                // keep the merkle root consistent with the shortened body, so
+                // the missing coinbase is the only rule violated
+                block.header.merkle_root =
+                    block.transactions.iter().map(AsRef::as_ref).collect();
+            }
+        }
+        FaultKind::DoubleSpend => {
+            // duplicate a spending transaction so its input is spent twice
+            if let Some(spend) = block
+                .transactions
+                .iter()
+                .find(|tx| tx.inputs().iter().any(|input| input.outpoint().is_some()))
+                .cloned()
+            {
+                block.transactions.push(spend);
+                // keep the merkle root consistent with the duplicated body, so
+                // the double spend is the only rule violated
+                block.header.merkle_root =
+                    block.transactions.iter().map(AsRef::as_ref).collect();
+            }
+        }
+        FaultKind::OverspentValuePool => {
+            // duplicate the outputs of a transaction so it spends more than its
+            // inputs (and more than the value pool can cover)
+            if let Some(tx) = block
+                .transactions
+                .iter_mut()
+                .find(|tx| !tx.outputs().is_empty())
+            {
+                let tx = Arc::make_mut(tx);
+                let extra = tx.outputs().to_vec();
+                tx.outputs_mut().extend(extra);
+                // the mutated transaction changes its hash, so recompute the
+                // merkle root and leave the value-pool overflow as the only
+                // rule violated
+                block.header.merkle_root =
+                    block.transactions.iter().map(AsRef::as_ref).collect();
+            }
+        }
+        FaultKind::TamperedCommitment => {
+            // corrupt the chain history / tx-auth commitment
+            block.header.commitment_bytes[0] ^= 0xff;
+        }
+    }
 
-                // delete invalid transactions
-                block.transactions = new_transactions;
-
-                // fix commitment (must be done after finishing changing the block)
-                if generate_valid_commitments {
-                    let current_height = block.coinbase_height().unwrap();
-                    let heartwood_height = NetworkUpgrade::Heartwood
-                        .activation_height(current.network)
-                        .unwrap();
-                    let nu5_height = NetworkUpgrade::Nu5.activation_height(current.network);
-                    match current_height.cmp(&heartwood_height) {
-                        std::cmp::Ordering::Less => {
-                            // In pre-Heartwood blocks this is the Sapling note commitment tree root.
-                            // We don't validate it since we checkpoint on Canopy, but it
-                            // needs to be well-formed, i.e. smaller than 𝑞_J, so we
-                            // arbitrarily set it to 1.
-                            block.header.commitment_bytes = [0u8; 32];
-                            block.header.commitment_bytes[0] = 1;
-                        }
-                        std::cmp::Ordering::Equal => {
-                            // The Heartwood activation block has a hardcoded all-zeroes commitment.
-                            block.header.commitment_bytes = [0u8; 32];
-                        }
-                        std::cmp::Ordering::Greater => {
-                            // Set the correct commitment bytes according to the network upgrade.
-                            let history_tree_root = match &history_tree {
-                                Some(tree) => tree.hash().unwrap_or_else(|| [0u8; 32].into()),
-                                None => [0u8; 32].into(),
-                            };
-                            if nu5_height.is_some() && current_height >= nu5_height.unwrap() {
-                                // From zebra-state/src/service/check.rs
-                                let auth_data_root = block.auth_data_root();
-                                let hash_block_commitments =
-                                    ChainHistoryBlockTxAuthCommitmentHash::from_commitments(
-                                        &history_tree_root,
-                                        &auth_data_root,
-                                    );
-                                block.header.commitment_bytes = hash_block_commitments.into();
-                            } else {
-                                block.header.commitment_bytes = history_tree_root.into();
-                            }
-                        }
+    InjectedFault {
+        block_index: index,
+        height,
+        fault,
+    }
+}
+
+/// Fix up a freshly generated chain of `(height, block)` pairs so it obeys more
+/// consensus rules, returning the fixed blocks.
+///
+/// `previous_block_hash` is the hash the first block should chain onto, or
+/// `None` to leave the first block's previous hash as generated (the start of a
+/// fresh chain). The UTXO set, chain value pools, note commitment trees and
+/// history tree are all created fresh here, so each call produces an internally
+/// consistent branch independent of any other.
+///
+/// See [`Block::partial_chain_strategy`] for the meaning of the remaining
+/// arguments.
+#[allow(clippy::type_complexity)]
+fn fixup_generated_chain<F, T, E>(
+    mut vec: Vec<(Height, Block)>,
+    network: Network,
+    mut previous_block_hash: Option<block::Hash>,
+    check_transparent_coinbase_spend: F,
+    generate_valid_commitments: bool,
+    generate_valid_merkle_root: bool,
+    generate_valid_shielded_spends: bool,
+) -> Vec<Arc<Block>>
+where
+    F: Fn(
+            transparent::OutPoint,
+            transparent::CoinbaseSpendRestriction,
+            transparent::OrderedUtxo,
+        ) -> Result<T, E>
+        + Copy
+        + 'static,
+{
+    let mut utxos = HashMap::new();
+    let mut chain_value_pools = ValueBalance::zero();
+    let mut sapling_tree = sapling::tree::NoteCommitmentTree::default();
+    let mut orchard_tree = orchard::tree::NoteCommitmentTree::default();
+    // The history tree usually takes care of "creating itself". But this
+    // only works when blocks are pushed into it starting from genesis
+    // (or at least pre-Heartwood, where the tree is not required).
+    // However, this strategy can generate blocks from an arbitrary height,
+    // so we must wait for the first block to create the history tree from it.
+    // This is why `Option` is used here.
+    let mut history_tree: Option<HistoryTree> = None;
+
+    // Records the shielded anchors and nullifiers created by earlier blocks, so
+    // later shielded spends can reference real anchors and re-emit matching
+    // nullifiers. Only populated when `generate_valid_shielded_spends` is set.
+    let mut shielded_spends = ShieldedSpendState::default();
+
+    for (height, block) in vec.iter_mut() {
+        // fixup the previous block hash
+        if let Some(previous_block_hash) = previous_block_hash {
+            block.header.previous_block_hash = previous_block_hash;
+        }
+
+        let mut new_transactions = Vec::new();
+        for (tx_index_in_block, transaction) in block.transactions.drain(..).enumerate() {
+            let mut transaction = (*transaction).clone();
+
+            // rewrite a fraction of this transaction's shielded spends to
+            // reference anchors and nullifiers created by earlier blocks
+            if generate_valid_shielded_spends && *height > Height(0) {
+                shielded_spends.fix_shielded_spends(&mut transaction);
+            }
+
+            if let Some(transaction) = fix_generated_transaction(
+                transaction,
+                tx_index_in_block,
+                *height,
+                &mut chain_value_pools,
+                &mut utxos,
+                check_transparent_coinbase_spend,
+            ) {
+                // The FinalizedState does not update the note commitment trees with the genesis block,
+                // because it doesn't need to (the trees are not used at that point) and updating them
+                // would be awkward since the genesis block is handled separatedly there.
+                // This forces us to skip the genesis block here too in order to able to use
+                // this to test the finalized state.
+                // The trees must also be kept up to date when generating valid
+                // shielded spends, so the anchors recorded below are real.
+                if (generate_valid_commitments || generate_valid_shielded_spends)
+                    && *height != Height(0)
+                {
+                    for sapling_note_commitment in transaction.sapling_note_commitments() {
+                        sapling_tree.append(*sapling_note_commitment).unwrap();
                     }
-                    // update history tree for the next block
-                    if history_tree.is_none() {
-                        history_tree = Some(
-                            HistoryTree::from_block(
-                                current.network,
-                                Arc::new(block.clone()),
-                                &sapling_tree.root(),
-                                &orchard_tree.root(),
-                            )
-                            .unwrap(),
-                        );
-                    } else {
-                        history_tree
-                            .as_mut()
-                            .unwrap()
-                            .push(
-                                current.network,
-                                Arc::new(block.clone()),
-                                sapling_tree.root(),
-                                orchard_tree.root(),
-                            )
-                            .unwrap();
+                    for orchard_note_commitment in transaction.orchard_note_commitments() {
+                        orchard_tree.append(*orchard_note_commitment).unwrap();
                     }
                 }
 
-                // now that we've made all the changes, calculate our block hash,
-                // so the next block can use it
-                previous_block_hash = Some(block.hash());
+                new_transactions.push(Arc::new(transaction));
             }
-            SummaryDebug(
-                vec.into_iter()
-                    .map(|(_height, block)| Arc::new(block))
-                    .collect(),
-            )
+        }
+
+        // record the anchors and nullifiers this block creates, once the whole
+        // block is in the trees, so only later blocks can spend them
+        if generate_valid_shielded_spends && *height != Height(0) {
+            for transaction in &new_transactions {
+                shielded_spends.record(transaction, &sapling_tree, &orchard_tree);
+            }
+        }
+
+        // delete invalid transactions
+        block.transactions = new_transactions;
+
+        // recompute the transaction merkle root from the final transactions,
+        // so the header stays consistent with the (possibly mutated) body
+        if generate_valid_merkle_root {
+            block.header.merkle_root = block.transactions.iter().map(AsRef::as_ref).collect();
+        }
+
+        // fix commitment (must be done after finishing changing the block)
+        if generate_valid_commitments {
+            fix_block_commitment(block, network, history_tree.as_ref());
+
+            // update history tree for the next block
+            if history_tree.is_none() {
+                history_tree = Some(
+                    HistoryTree::from_block(
+                        network,
+                        Arc::new(block.clone()),
+                        &sapling_tree.root(),
+                        &orchard_tree.root(),
+                    )
+                    .unwrap(),
+                );
+            } else {
+                history_tree
+                    .as_mut()
+                    .unwrap()
+                    .push(
+                        network,
+                        Arc::new(block.clone()),
+                        sapling_tree.root(),
+                        orchard_tree.root(),
+                    )
+                    .unwrap();
+            }
+        }
+
+        // now that we've made all the changes, calculate our block hash,
+        // so the next block can use it
+        previous_block_hash = Some(block.hash());
+    }
+
+    vec.into_iter().map(|(_height, block)| Arc::new(block)).collect()
+}
+
+/// Sets `block`'s `commitment_bytes` to the correct [`Commitment`] for its
+/// height and network upgrade, deriving the chain-history root from
+/// `history_tree`.
+///
+/// When `history_tree` is `None` — for example a block assembled on its own,
+/// outside a chain — an empty history root is used, matching the first block of
+/// a generated chain.
+///
+/// A block without a coinbase transaction has no height to derive the
+/// commitment from, so its arbitrary `commitment_bytes` are left unchanged.
+fn fix_block_commitment(block: &mut Block, network: Network, history_tree: Option<&HistoryTree>) {
+    let current_height = match block.coinbase_height() {
+        Some(height) => height,
+        None => return,
+    };
+    let heartwood_height = NetworkUpgrade::Heartwood
+        .activation_height(network)
+        .unwrap();
+    let nu5_height = NetworkUpgrade::Nu5.activation_height(network);
+    match current_height.cmp(&heartwood_height) {
+        std::cmp::Ordering::Less => {
+            // In pre-Heartwood blocks this is the Sapling note commitment tree root.
+            // We don't validate it since we checkpoint on Canopy, but it
+            // needs to be well-formed, i.e. smaller than 𝑞_J, so we
+            // arbitrarily set it to 1.
+            block.header.commitment_bytes = [0u8; 32];
+            block.header.commitment_bytes[0] = 1;
+        }
+        std::cmp::Ordering::Equal => {
+            // The Heartwood activation block has a hardcoded all-zeroes commitment.
+            block.header.commitment_bytes = [0u8; 32];
+        }
+        std::cmp::Ordering::Greater => {
+            // Set the correct commitment bytes according to the network upgrade.
+            let history_tree_root = match history_tree {
+                Some(tree) => tree.hash().unwrap_or_else(|| [0u8; 32].into()),
+                None => [0u8; 32].into(),
+            };
+            if nu5_height.is_some() && current_height >= nu5_height.unwrap() {
+                // From zebra-state/src/service/check.rs
+                let auth_data_root = block.auth_data_root();
+                let hash_block_commitments =
+                    ChainHistoryBlockTxAuthCommitmentHash::from_commitments(
+                        &history_tree_root,
+                        &auth_data_root,
+                    );
+                block.header.commitment_bytes = hash_block_commitments.into();
+            } else {
+                block.header.commitment_bytes = history_tree_root.into();
+            }
+        }
+    }
+}
+
+/// Assembles a [`Block`] whose [`Header`] is consistent with its body, like a
+/// miner's block template.
+///
+/// Given the `header` skeleton produced by [`Header::arbitrary_with`] and the
+/// block's `transactions`, this recomputes the real transaction merkle root and
+/// the correct [`Commitment`] bytes for the block's height and network upgrade
+/// (using an empty chain-history root, since a standalone block has no chain
+/// context). The result can feed header+body validation paths that check the
+/// merkle root or chain-history commitment, rather than only serialization
+/// round-trips.
+///
+/// Blocks generated without a coinbase transaction keep their arbitrary
+/// commitment bytes, since there is no height to derive a commitment from.
+pub fn assemble_block(
+    network: Network,
+    mut header: Header,
+    transactions: Vec<Arc<Transaction>>,
+) -> Block {
+    // the merkle root commits to the final transaction list
+    header.merkle_root = transactions.iter().map(AsRef::as_ref).collect();
+
+    let mut block = Block {
+        header,
+        transactions,
+    };
+
+    // the commitment depends on the assembled block's height and auth data
+    fix_block_commitment(&mut block, network, None);
+
+    block
+}
+
+/// Tracks the shielded anchors and nullifiers created by earlier blocks in a
+/// generated chain, so later shielded spends can be rewritten to reference
+/// real anchors and re-emit matching nullifiers.
+///
+/// Only used when [`LedgerState::generate_valid_shielded_spends`] is set.
+#[derive(Default)]
+struct ShieldedSpendState {
+    /// Sapling note commitment tree roots created by earlier blocks.
+    sapling_anchors: Vec<sapling::tree::Root>,
+
+    /// Orchard note commitment tree roots created by earlier blocks.
+    orchard_anchors: Vec<orchard::tree::Root>,
+
+    /// Sapling nullifiers revealed by earlier blocks.
+    sapling_nullifiers: Vec<sapling::Nullifier>,
+
+    /// Orchard nullifiers revealed by earlier blocks.
+    orchard_nullifiers: Vec<orchard::Nullifier>,
+
+    /// The number of transactions whose spends have been rewritten so far,
+    /// used to rewrite only a fraction of later spends.
+    fixed: usize,
+}
+
+impl ShieldedSpendState {
+    /// Record the anchors and nullifiers that `transaction` contributes once it
+    /// is added to the `sapling_tree`/`orchard_tree`, so later blocks can
+    /// reference them.
+    fn record(
+        &mut self,
+        transaction: &Transaction,
+        sapling_tree: &sapling::tree::NoteCommitmentTree,
+        orchard_tree: &orchard::tree::NoteCommitmentTree,
+    ) {
+        if transaction.sapling_note_commitments().count() > 0 {
+            self.sapling_anchors.push(sapling_tree.root());
+        }
+        if transaction.orchard_note_commitments().count() > 0 {
+            self.orchard_anchors.push(orchard_tree.root());
+        }
+
+        self.sapling_nullifiers
+            .extend(transaction.sapling_nullifiers().copied());
+        self.orchard_nullifiers
+            .extend(transaction.orchard_nullifiers().copied());
+    }
+
+    /// Rewrite a fraction of `transaction`'s shielded spends to reference
+    /// anchors (and sometimes nullifiers) created by earlier blocks.
+    ///
+    /// A rotating counter splits the eligible transactions three ways so
+    /// generated chains exercise every cross-block shielded-spend path:
+    ///
+    /// * one third keep their arbitrary (invalid) anchor, exercising anchor
+    ///   rejection;
+    /// * one third repoint to a real earlier anchor while keeping each spend's
+    ///   own fresh nullifier, yielding a genuinely valid cross-block spend; and
+    /// * one third repoint to a real earlier anchor and replay a nullifier an
+    ///   earlier block already revealed, yielding a cross-block double spend.
+    fn fix_shielded_spends(&mut self, transaction: &mut Transaction) {
+        let which = self.fixed % 3;
+        self.fixed += 1;
+
+        // one third keep their arbitrary anchor, so the invalid-anchor path is
+        // still exercised
+        if which == 0 {
+            return;
+        }
+
+        // replay an earlier nullifier only in the double-spend third; the valid
+        // third keeps each spend's own fresh nullifier alongside the real anchor
+        let replay_nullifier = which == 2;
+
+        if let Some(sapling) = transaction.sapling_shielded_data_mut() {
+            if let Some(anchor) = self.sapling_anchors.last().copied() {
+                sapling.shared_anchor = anchor;
+            }
+            if replay_nullifier {
+                if let (Some(nullifier), Some(spend)) =
+                    (self.sapling_nullifiers.first().copied(), sapling.spends_mut().next())
+                {
+                    spend.nullifier = nullifier;
+                }
+            }
+        }
+
+        if let Some(orchard) = transaction.orchard_shielded_data_mut() {
+            if let Some(anchor) = self.orchard_anchors.last().copied() {
+                orchard.shared_anchor = anchor.into();
+            }
+            if replay_nullifier {
+                if let (Some(nullifier), Some(authorized_action)) = (
+                    self.orchard_nullifiers.first().copied(),
+                    orchard.actions.iter_mut().next(),
+                ) {
+                    authorized_action.action.nullifier = nullifier;
+                }
+            }
+        }
+    }
+}
+
+/// A set of unspent [`transparent::OrderedUtxo`]s that the transaction-fixing
+/// code spends from and adds to while assembling generated chains.
+///
+/// The default implementation is a plain [`HashMap`], which keeps the whole
+/// UTXO set resident in memory. Property tests that generate very long chains
+/// can instead use a backing store that spills to disk (see [`DiskUtxoStore`]
+/// behind the `lmdb` feature) so the set doesn't have to fit in RAM, avoiding
+/// the unbounded growth that made the old rust-bitcoin `UtxoSet` unusable for
+/// large fuzzing runs.
+pub trait UtxoStore {
+    /// Returns a copy of the UTXO for `outpoint`, if it is unspent.
+    fn get(&self, outpoint: &transparent::OutPoint) -> Option<transparent::OrderedUtxo>;
+
+    /// Removes the UTXO for `outpoint`, marking it spent, and returns it.
+    fn remove(&mut self, outpoint: &transparent::OutPoint) -> Option<transparent::OrderedUtxo>;
+
+    /// Inserts the newly created `utxo` at `outpoint`.
+    fn insert(&mut self, outpoint: transparent::OutPoint, utxo: transparent::OrderedUtxo);
+
+    /// Adds every `(outpoint, utxo)` pair produced by `outputs` to the store.
+    fn extend<I>(&mut self, outputs: I)
+    where
+        I: IntoIterator<Item = (transparent::OutPoint, transparent::OrderedUtxo)>,
+    {
+        for (outpoint, utxo) in outputs {
+            self.insert(outpoint, utxo);
+        }
+    }
+
+    /// Returns an arbitrary unspent `(outpoint, utxo)` in the store's native
+    /// order, if any remain.
+    ///
+    /// Stores implement this without materialising the whole set, so the
+    /// default [`CoinSelectionStrategy::HashOrder`] stays cheap per spend even
+    /// on disk-backed stores.
+    fn first_candidate(&self) -> Option<(transparent::OutPoint, transparent::OrderedUtxo)>;
+
+    /// Returns every candidate `(outpoint, utxo)` pair available for coin
+    /// selection.
+    ///
+    /// The order is unspecified but deterministic for a given store state, so
+    /// [`CoinSelectionStrategy`] can impose its own stable ordering on top.
+    /// This materialises the whole set, so it is only used by strategies that
+    /// must scan every UTXO (such as
+    /// [`CoinSelectionStrategy::SmallestAboveDustFirst`]); the default
+    /// single-candidate path uses [`UtxoStore::first_candidate`] instead.
+    fn candidates(&self) -> Vec<(transparent::OutPoint, transparent::OrderedUtxo)>;
+}
+
+impl UtxoStore for HashMap<transparent::OutPoint, transparent::OrderedUtxo> {
+    fn get(&self, outpoint: &transparent::OutPoint) -> Option<transparent::OrderedUtxo> {
+        HashMap::get(self, outpoint).cloned()
+    }
+
+    fn remove(&mut self, outpoint: &transparent::OutPoint) -> Option<transparent::OrderedUtxo> {
+        HashMap::remove(self, outpoint)
+    }
+
+    fn insert(&mut self, outpoint: transparent::OutPoint, utxo: transparent::OrderedUtxo) {
+        HashMap::insert(self, outpoint, utxo);
+    }
+
+    fn first_candidate(&self) -> Option<(transparent::OutPoint, transparent::OrderedUtxo)> {
+        self.iter()
+            .next()
+            .map(|(outpoint, utxo)| (*outpoint, utxo.clone()))
+    }
+
+    fn candidates(&self) -> Vec<(transparent::OutPoint, transparent::OrderedUtxo)> {
+        self.iter()
+            .map(|(outpoint, utxo)| (*outpoint, utxo.clone()))
+            .collect()
+    }
+}
+
+/// A [`UtxoStore`] that keeps the UTXO set in an LMDB database on disk instead
+/// of in resident memory, for property tests that generate multi-thousand-block
+/// chains.
+///
+/// Entries are serialised with [`bincode`], keyed by the serialised
+/// [`transparent::OutPoint`]. The temporary database is removed when the store
+/// is dropped.
+#[cfg(feature = "lmdb")]
+pub struct DiskUtxoStore {
+    _dir: tempfile::TempDir,
+    env: lmdb::Environment,
+    db: lmdb::Database,
+}
+
+#[cfg(feature = "lmdb")]
+impl DiskUtxoStore {
+    /// Creates an empty disk-backed store in a fresh temporary directory.
+    pub fn new() -> std::io::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let env = lmdb::Environment::new()
+            .set_map_size(1 << 34)
+            .open(dir.path())
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
+        let db = env
+            .open_db(None)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
+
+        Ok(Self {
+            _dir: dir,
+            env,
+            db,
         })
-        .boxed()
+    }
+
+    fn key(outpoint: &transparent::OutPoint) -> Vec<u8> {
+        bincode::serialize(outpoint).expect("outpoints serialize")
+    }
+}
+
+#[cfg(feature = "lmdb")]
+impl UtxoStore for DiskUtxoStore {
+    fn get(&self, outpoint: &transparent::OutPoint) -> Option<transparent::OrderedUtxo> {
+        use lmdb::Transaction as _;
+
+        let tx = self.env.begin_ro_txn().expect("read transaction");
+        let utxo = match tx.get(self.db, &Self::key(outpoint)) {
+            Ok(bytes) => Some(bincode::deserialize(bytes).expect("stored utxos deserialize")),
+            Err(lmdb::Error::NotFound) => None,
+            Err(error) => panic!("lmdb read failed: {error}"),
+        };
+        tx.abort();
+        utxo
+    }
+
+    fn remove(&mut self, outpoint: &transparent::OutPoint) -> Option<transparent::OrderedUtxo> {
+        use lmdb::Transaction as _;
+
+        let key = Self::key(outpoint);
+        let mut tx = self.env.begin_rw_txn().expect("write transaction");
+        let utxo = match tx.get(self.db, &key) {
+            Ok(bytes) => Some(bincode::deserialize(bytes).expect("stored utxos deserialize")),
+            Err(lmdb::Error::NotFound) => None,
+            Err(error) => panic!("lmdb read failed: {error}"),
+        };
+        if utxo.is_some() {
+            tx.del(self.db, &key, None).expect("lmdb delete succeeds");
+        }
+        tx.commit().expect("lmdb commit succeeds");
+        utxo
+    }
+
+    fn insert(&mut self, outpoint: transparent::OutPoint, utxo: transparent::OrderedUtxo) {
+        use lmdb::Transaction as _;
+
+        let bytes = bincode::serialize(&utxo).expect("utxos serialize");
+        let mut tx = self.env.begin_rw_txn().expect("write transaction");
+        tx.put(
+            self.db,
+            &Self::key(&outpoint),
+            &bytes,
+            lmdb::WriteFlags::empty(),
+        )
+        .expect("lmdb put succeeds");
+        tx.commit().expect("lmdb commit succeeds");
+    }
+
+    fn first_candidate(&self) -> Option<(transparent::OutPoint, transparent::OrderedUtxo)> {
+        use lmdb::{Cursor as _, Transaction as _};
+
+        let tx = self.env.begin_ro_txn().expect("read transaction");
+        let first = {
+            let mut cursor = tx.open_ro_cursor(self.db).expect("cursor opens");
+            cursor.iter_start().next().map(|entry| {
+                let (key, value) = entry.expect("lmdb iteration succeeds");
+                (
+                    bincode::deserialize(key).expect("stored outpoints deserialize"),
+                    bincode::deserialize(value).expect("stored utxos deserialize"),
+                )
+            })
+        };
+        tx.abort();
+        first
+    }
+
+    fn candidates(&self) -> Vec<(transparent::OutPoint, transparent::OrderedUtxo)> {
+        use lmdb::{Cursor as _, Transaction as _};
+
+        let tx = self.env.begin_ro_txn().expect("read transaction");
+        let candidates = {
+            let mut cursor = tx.open_ro_cursor(self.db).expect("cursor opens");
+            cursor
+                .iter_start()
+                .map(|entry| {
+                    let (key, value) = entry.expect("lmdb iteration succeeds");
+                    (
+                        bincode::deserialize(key).expect("stored outpoints deserialize"),
+                        bincode::deserialize(value).expect("stored utxos deserialize"),
+                    )
+                })
+                .collect()
+        };
+        tx.abort();
+        candidates
+    }
+}
+
+/// The 32-byte digest used for [`UtreexoAccumulator`] leaves and internal nodes.
+type UtreexoHash = [u8; 32];
+
+/// The side a sibling sits on, relative to the node being proven, in a
+/// [`UtreexoProof`] path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UtreexoSide {
+    /// The sibling is the left child; the proven node is the right child.
+    Left,
+    /// The sibling is the right child; the proven node is the left child.
+    Right,
+}
+
+/// An inclusion proof for a single leaf in a [`UtreexoAccumulator`].
+///
+/// `path` lists the sibling hashes from the leaf up to the root of the tree the
+/// leaf belongs to; its length is the height of that tree.
+#[derive(Clone, Debug)]
+pub struct UtreexoProof {
+    /// The hash of the leaf being proven (see [`UtreexoAccumulator::leaf_hash`]).
+    pub leaf: UtreexoHash,
+    /// The sibling hashes from the leaf to the root, innermost first.
+    pub path: Vec<(UtreexoHash, UtreexoSide)>,
+}
+
+/// The error returned when a [`UtreexoProof`] does not match the accumulator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UtreexoProofError;
+
+impl std::fmt::Display for UtreexoProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("utreexo proof does not match any accumulator root")
     }
 }
 
-/// Fix `transaction` so it obeys more consensus rules.
+impl std::error::Error for UtreexoProofError {}
+
+/// A utreexo-style hash accumulator: an alternative, constant-memory
+/// representation of the spendable UTXO set.
 ///
+/// Instead of a live [`HashMap`], the set is a forest of perfect binary Merkle
+/// trees — one tree per set bit in the leaf count, exactly like the binary
+/// representation of that count. Only the roots are kept, so membership is
+/// validated with `O(log n)` inclusion proofs supplied by the caller rather
+/// than by holding every output in memory. This gives Zebra a testbed for
+/// utreexo consensus behaviour and far cheaper large-chain fuzzing alongside
+/// the in-memory [`UtxoStore`] implementations.
+#[derive(Clone, Debug, Default)]
+pub struct UtreexoAccumulator {
+    /// The forest roots, indexed by tree height. `roots[h]` is `Some` exactly
+    /// when a tree of height `h` is currently present.
+    roots: Vec<Option<UtreexoHash>>,
+}
+
+impl UtreexoAccumulator {
+    /// Creates an empty accumulator.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hashes an `outpoint`/`output` pair into an accumulator leaf.
+    pub fn leaf_hash(
+        outpoint: &transparent::OutPoint,
+        output: &transparent::Output,
+    ) -> UtreexoHash {
+        use serialization::ZcashSerialize;
+
+        let mut state = blake2b_simd::Params::new()
+            .hash_length(32)
+            .personal(b"zebra-utxo-leaf\0")
+            .to_state();
+        state.update(
+            &outpoint
+                .zcash_serialize_to_vec()
+                .expect("outpoints serialize"),
+        );
+        state.update(&output.zcash_serialize_to_vec().expect("outputs serialize"));
+
+        state
+            .finalize()
+            .as_bytes()
+            .try_into()
+            .expect("blake2b output is 32 bytes")
+    }
+
+    /// Hashes two child nodes into their parent node.
+    fn hash_nodes(left: &UtreexoHash, right: &UtreexoHash) -> UtreexoHash {
+        let mut state = blake2b_simd::Params::new()
+            .hash_length(32)
+            .personal(b"zebra-utxo-node\0")
+            .to_state();
+        state.update(left);
+        state.update(right);
+
+        state
+            .finalize()
+            .as_bytes()
+            .try_into()
+            .expect("blake2b output is 32 bytes")
+    }
+
+    /// Adds the UTXO created by `outpoint`/`output` to the accumulator.
+    ///
+    /// A new leaf is inserted at height 0; while a root already exists at the
+    /// current height, the two are hashed into a parent that carries up to the
+    /// next height, exactly like adding one to a binary counter.
+    pub fn add(&mut self, outpoint: &transparent::OutPoint, output: &transparent::Output) {
+        let leaf = Self::leaf_hash(outpoint, output);
+        self.insert_root(0, leaf);
+    }
+
+    /// Inserts `node` as a root of height `height`, carrying up through any
+    /// existing roots at the same height.
+    fn insert_root(&mut self, mut height: usize, mut node: UtreexoHash) {
+        while height < self.roots.len() {
+            match self.roots[height].take() {
+                // carry: the existing tree is the left child of the new parent
+                Some(existing) => {
+                    node = Self::hash_nodes(&existing, &node);
+                    height += 1;
+                }
+                None => {
+                    self.roots[height] = Some(node);
+                    return;
+                }
+            }
+        }
+
+        self.roots.resize(height + 1, None);
+        self.roots[height] = Some(node);
+    }
+
+    /// Spends the leaf described by `proof`, shrinking the forest.
+    ///
+    /// The sibling path is folded up to recompute the containing tree's root; if
+    /// it doesn't match a present root, the proof is rejected. Otherwise the
+    /// root is removed and each sibling along the path is reinserted as an
+    /// independent root at its own height, replacing the spent leaf's
+    /// contribution with its sibling subtrees.
+    pub fn spend(&mut self, proof: &UtreexoProof) -> Result<(), UtreexoProofError> {
+        let height = proof.path.len();
+
+        let mut node = proof.leaf;
+        for (sibling, side) in &proof.path {
+            node = match side {
+                UtreexoSide::Left => Self::hash_nodes(sibling, &node),
+                UtreexoSide::Right => Self::hash_nodes(&node, sibling),
+            };
+        }
+
+        if self.roots.get(height).copied().flatten() != Some(node) {
+            return Err(UtreexoProofError);
+        }
+
+        self.roots[height] = None;
+        for (index, (sibling, _side)) in proof.path.iter().enumerate() {
+            self.insert_root(index, *sibling);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the current forest roots, from lowest to highest tree.
+    pub fn roots(&self) -> Vec<UtreexoHash> {
+        self.roots.iter().flatten().copied().collect()
+    }
+}
+
 /// Spends [`OutPoint`]s from `utxos`, and adds newly created outputs.
 ///
 /// If the transaction can't be fixed, returns `None`.
-pub fn fix_generated_transaction<F, T, E>(
+pub fn fix_generated_transaction<F, T, E, S>(
     mut transaction: Transaction,
     tx_index_in_block: usize,
     height: Height,
     chain_value_pools: &mut ValueBalance<NonNegative>,
-    utxos: &mut HashMap<transparent::OutPoint, transparent::OrderedUtxo>,
+    utxos: &mut S,
     check_transparent_coinbase_spend: F,
 ) -> Option<Transaction>
 where
+    S: UtxoStore,
     F: Fn(
             transparent::OutPoint,
             transparent::CoinbaseSpendRestriction,
@@ -581,6 +1543,7 @@ where
                 height,
                 utxos,
                 check_transparent_coinbase_spend,
+                CoinSelectionStrategy::default(),
             ) {
                 input.set_outpoint(selected_outpoint);
                 new_inputs.push(input);
@@ -625,19 +1588,70 @@ where
     }
 }
 
-/// Find a valid [`OutPoint`] in `utxos` to spend in `transaction`.
+/// The dust threshold used by [`CoinSelectionStrategy::SmallestAboveDustFirst`],
+/// in zatoshis.
+const COIN_SELECTION_DUST_THRESHOLD: i64 = 54;
+
+/// Controls how [`find_valid_utxo_for_spend`] picks a UTXO to spend.
+#[derive(Clone, Copy, Debug)]
+pub enum CoinSelectionStrategy {
+    /// Try only the UTXO returned by `utxos.first_candidate()`.
+    ///
+    /// This is the historical behaviour, and the default, so existing tests are
+    /// unchanged: the generator examined a single hash-set-order candidate and
+    /// gave up if it wasn't spendable, rather than scanning the whole set.
+    HashOrder {
+        /// The historical cap on how many times the single candidate was
+        /// retried before giving up. Retrying the same candidate can't change
+        /// the outcome, so it is kept only for API symmetry with
+        /// [`CoinSelectionStrategy::SmallestAboveDustFirst`].
+        max_attempts: usize,
+    },
+
+    /// Pick the smallest UTXO that stays above the dust threshold after the
+    /// spend, first, like real wallets do.
+    ///
+    /// Candidates are sorted ascending by `(value, OutPoint)`, which is stable
+    /// and deterministic so failing proptest cases shrink reproducibly.
+    SmallestAboveDustFirst {
+        /// The maximum number of candidates to try before giving up.
+        max_attempts: usize,
+    },
+}
+
+impl Default for CoinSelectionStrategy {
+    fn default() -> Self {
+        CoinSelectionStrategy::HashOrder { max_attempts: 100 }
+    }
+}
+
+impl CoinSelectionStrategy {
+    /// The maximum number of candidates this strategy will try.
+    fn max_attempts(&self) -> usize {
+        match self {
+            CoinSelectionStrategy::HashOrder { max_attempts }
+            | CoinSelectionStrategy::SmallestAboveDustFirst { max_attempts } => *max_attempts,
+        }
+    }
+}
+
+/// Find a valid [`OutPoint`] in `utxos` to spend in `transaction`, using
+/// `strategy` to pick the candidate.
 ///
 /// Modifies `transaction` and updates `spend_restriction` if needed.
 ///
-/// If there is no valid output, or many search attempts have failed, returns `None`.
-pub fn find_valid_utxo_for_spend<F, T, E>(
+/// If there is no valid output, or the strategy's attempt cap is reached,
+/// returns `None`.
+pub fn find_valid_utxo_for_spend<F, T, E, S>(
     transaction: &mut Transaction,
     spend_restriction: &mut CoinbaseSpendRestriction,
     spend_height: Height,
-    utxos: &HashMap<transparent::OutPoint, transparent::OrderedUtxo>,
+    utxos: &S,
     check_transparent_coinbase_spend: F,
+    strategy: CoinSelectionStrategy,
 ) -> Option<transparent::OutPoint>
 where
+    S: UtxoStore,
     F: Fn(
             transparent::OutPoint,
             transparent::CoinbaseSpendRestriction,
@@ -648,30 +1662,68 @@ where
 {
     let has_shielded_outputs = transaction.has_shielded_outputs();
     let delete_transparent_outputs = CoinbaseSpendRestriction::OnlyShieldedOutputs { spend_height };
-    let mut attempts: usize = 0;
-
-    // choose an arbitrary spendable UTXO, in hash set order
-    while let Some((candidate_outpoint, candidate_utxo)) = utxos.iter().next() {
-        attempts += 1;
-
-        // Avoid O(n^2) algorithmic complexity by giving up early,
-        // rather than exhausively checking the entire UTXO set
-        if attempts > 100 {
-            return None;
+    let max_attempts = strategy.max_attempts();
+
+    // Build the candidate iteration order for this strategy.
+    let candidates: Vec<transparent::OutPoint> = match strategy {
+        // hash-set order: only ever try the first candidate, exactly as the
+        // historical `while let Some(_) = utxos.iter().next()` loop did (it
+        // never advanced, so retries examined the same UTXO and couldn't
+        // change the result). Using `first_candidate` keeps this O(1) per
+        // spend instead of materialising the whole store.
+        CoinSelectionStrategy::HashOrder { .. } => utxos
+            .first_candidate()
+            .map(|(outpoint, _utxo)| outpoint)
+            .into_iter()
+            .collect(),
+
+        // smallest-above-dust-after-spend-first: the algorithm real wallets use
+        CoinSelectionStrategy::SmallestAboveDustFirst { .. } => {
+            let dust = Amount::<NonNegative>::try_from(COIN_SELECTION_DUST_THRESHOLD)
+                .expect("dust threshold is a valid amount");
+
+            let mut candidates: Vec<(Amount<NonNegative>, transparent::OutPoint)> = utxos
+                .candidates()
+                .into_iter()
+                .filter(|(_outpoint, utxo)| {
+                    // The spend stays above dust only if the candidate is itself
+                    // above dust. In the `OnlyShieldedOutputs` branch the
+                    // transparent outputs are deleted, so the whole candidate
+                    // value is spent into the shielded pool; the dust check on
+                    // the candidate value still applies.
+                    utxo.utxo.output.value() > dust
+                })
+                .map(|(outpoint, utxo)| (utxo.utxo.output.value(), outpoint))
+                .collect();
+
+            // stable, deterministic ordering by (value, OutPoint)
+            candidates.sort_unstable();
+
+            candidates
+                .into_iter()
+                .map(|(_value, outpoint)| outpoint)
+                .take(max_attempts)
+                .collect()
         }
+    };
+
+    for candidate_outpoint in candidates {
+        let candidate_utxo = utxos
+            .get(&candidate_outpoint)
+            .expect("candidate came from utxos");
 
         // try the utxo as-is, then try it with deleted transparent outputs
         if check_transparent_coinbase_spend(
-            *candidate_outpoint,
+            candidate_outpoint,
             *spend_restriction,
             candidate_utxo.clone(),
         )
         .is_ok()
         {
-            return Some(*candidate_outpoint);
+            return Some(candidate_outpoint);
         } else if has_shielded_outputs
             && check_transparent_coinbase_spend(
-                *candidate_outpoint,
+                candidate_outpoint,
                 delete_transparent_outputs,
                 candidate_utxo.clone(),
             )
@@ -680,7 +1732,7 @@ where
             *transaction.outputs_mut() = Vec::new();
             *spend_restriction = delete_transparent_outputs;
 
-            return Some(*candidate_outpoint);
+            return Some(candidate_outpoint);
         }
     }
 
@@ -691,15 +1743,57 @@ impl Arbitrary for Commitment {
     type Parameters = ();
 
     fn arbitrary_with(_args: ()) -> Self::Strategy {
-        (any::<[u8; 32]>(), any::<Network>(), any::<Height>())
-            .prop_map(|(commitment_bytes, network, block_height)| {
-                if block_height == Heartwood.activation_height(network).unwrap() {
-                    Commitment::ChainHistoryActivationReserved
-                } else {
-                    Commitment::from_bytes(commitment_bytes, network, block_height)
-                        .expect("unexpected failure in from_bytes parsing")
-                }
-            })
+        // Sample a height/network to pick the era, plus the bytes each variant
+        // needs: a Sapling root, a chain-history root, and an auth-data root.
+        (
+            any::<[u8; 32]>(),
+            any::<[u8; 32]>(),
+            any::<[u8; 32]>(),
+            any::<Network>(),
+            any::<Height>(),
+        )
+            .prop_map(
+                |(commitment_bytes, history_bytes, auth_bytes, network, block_height)| {
+                    // Construct the commitment variant that really belongs to
+                    // this height, rather than round-tripping random bytes, so
+                    // every era is exercised and each value is structurally valid.
+                    match NetworkUpgrade::current(network, block_height) {
+                        // Pre-Sapling this field is reserved and must be all zeroes.
+                        Genesis | BeforeOverwinter | Overwinter => {
+                            Commitment::PreSaplingReserved([0; 32])
+                        }
+
+                        // Sapling and Blossom commit to the final Sapling treestate;
+                        // `from_bytes` builds the `FinalSaplingRoot` for us.
+                        Sapling | Blossom => {
+                            Commitment::from_bytes(commitment_bytes, network, block_height)
+                                .expect("unexpected failure in from_bytes parsing")
+                        }
+
+                        // The Heartwood activation block has a reserved all-zeroes
+                        // commitment; every later Heartwood/Canopy block commits to
+                        // the chain history MMR root.
+                        Heartwood | Canopy => {
+                            if Some(block_height) == Heartwood.activation_height(network) {
+                                Commitment::ChainHistoryActivationReserved
+                            } else {
+                                Commitment::ChainHistoryRoot(ChainHistoryMmrRootHash::from(
+                                    history_bytes,
+                                ))
+                            }
+                        }
+
+                        // NU5 onward combines the chain history root with the block's
+                        // auth data root; derive it from arbitrary values of each.
+                        _ => Commitment::ChainHistoryBlockTxAuthCommitment(
+                            ChainHistoryBlockTxAuthCommitmentHash::from_commitments(
+                                &ChainHistoryMmrRootHash::from(history_bytes),
+                                &merkle::AuthDataRoot::from(auth_bytes),
+                            ),
+                        ),
+                    }
+                },
+            )
             .boxed()
     }
 
@@ -0,0 +1,338 @@
+//! Async Halo2 batch verifier service
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use once_cell::sync::Lazy;
+use orchard::circuit::VerifyingKey;
+use tokio::sync::broadcast::{channel, error::RecvError, Sender};
+use tower::Service;
+use tower_batch::BatchControl;
+
+#[cfg(test)]
+mod tests;
+
+/// Re-exported for the tests and downstream fallback wiring.
+pub use std::future::ready;
+
+/// Re-exported so the batch window and per-proof fallback can be wired up by
+/// tests and downstream callers.
+pub use tower_batch::Batch;
+pub use tower_fallback::Fallback;
+
+/// The orchard proof verifying key.
+///
+/// Cached and shared by all verification tasks, because building it is
+/// expensive and its contents never change.
+pub static VERIFYING_KEY: Lazy<VerifyingKey> = Lazy::new(VerifyingKey::build);
+
+// === TYPES =================================================================
+
+/// A Halo2 verification item, used as the request type of the service.
+///
+/// This carries everything needed to verify the zk proof of a single orchard
+/// [`ShieldedData`](zebra_chain::orchard::ShieldedData) bundle: the public
+/// [`Instance`](orchard::circuit::Instance)s (one per action) and the proof
+/// itself.
+#[derive(Clone)]
+pub struct Item {
+    instances: Vec<orchard::circuit::Instance>,
+    proof: orchard::circuit::Proof,
+}
+
+impl fmt::Debug for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The proof bytes are large and opaque, so we only debug the instances.
+        f.debug_struct("Item")
+            .field("instances", &self.instances)
+            .finish()
+    }
+}
+
+impl Item {
+    /// Perform non-batched verification of this `Item`.
+    ///
+    /// This is useful (in combination with `Item::clone`) when implementing
+    /// fallback logic to handle individual failures in batch verification.
+    pub fn verify_single(&self, vk: &VerifyingKey) -> Result<(), halo2::plonk::Error> {
+        self.proof.verify(vk, &self.instances[..])
+    }
+}
+
+impl From<&zebra_chain::orchard::ShieldedData> for Item {
+    fn from(shielded_data: &zebra_chain::orchard::ShieldedData) -> Item {
+        use orchard::{circuit, note, primitives::redpallas, tree, value};
+
+        let anchor =
+            tree::Anchor::from_bytes(shielded_data.shared_anchor.into()).expect("valid anchor");
+
+        let enable_spends = shielded_data
+            .flags
+            .contains(zebra_chain::orchard::Flags::ENABLE_SPENDS);
+        let enable_outputs = shielded_data
+            .flags
+            .contains(zebra_chain::orchard::Flags::ENABLE_OUTPUTS);
+        let flags = circuit::Flags::from_parts(enable_spends, enable_outputs);
+
+        let instances = shielded_data
+            .actions()
+            .map(|action| {
+                circuit::Instance::from_parts(
+                    anchor,
+                    value::ValueCommitment::from_bytes(&action.cv.into())
+                        .expect("valid value commitment"),
+                    note::Nullifier::from_bytes(&action.nullifier.into())
+                        .expect("valid nullifier"),
+                    redpallas::VerificationKey::<redpallas::SpendAuth>::try_from(<[u8; 32]>::from(
+                        action.rk,
+                    ))
+                    .expect("valid spend auth verification key"),
+                    action.cm_x,
+                    flags,
+                )
+            })
+            .collect();
+
+        Item {
+            instances,
+            proof: circuit::Proof::new(shielded_data.proof.0.clone()),
+        }
+    }
+}
+
+/// An error that may occur while verifying Halo2 proofs.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Halo2Error {
+    #[error("the proof is invalid")]
+    InvalidProof,
+
+    #[error("the instances supplied to the verifier are inconsistent with the vk")]
+    InvalidInstances,
+
+    #[error("unexpected verification failure: {0}")]
+    Other(String),
+}
+
+impl From<halo2::plonk::Error> for Halo2Error {
+    fn from(err: halo2::plonk::Error) -> Halo2Error {
+        match err {
+            halo2::plonk::Error::ConstraintSystemFailure => Halo2Error::InvalidProof,
+            _ => Halo2Error::Other(format!("{err:?}")),
+        }
+    }
+}
+
+// === SERVICE ===============================================================
+
+/// The proofs accumulated over one async batch window.
+///
+/// Grouping proofs into a window amortizes async task scheduling, but the
+/// cryptographic check is still one [`orchard::circuit::Proof::verify`] per
+/// proof — this is *not* a single batched MSM. orchard's public API exposes no
+/// multi-proof verifier for standalone proofs: its batched path,
+/// [`BatchValidator`](orchard::bundle::BatchValidator), verifies a whole bundle
+/// (proof *and* signatures) keyed by the transaction sighash, which this
+/// proof-only service — and the [`Item`]s it receives — do not carry. So each
+/// queued proof is verified on its own; a single failing proof fails the
+/// window, and the [`Fallback`](tower_fallback::Fallback) the service is
+/// wrapped in then re-checks each proof to isolate the offender.
+#[derive(Default)]
+struct ProofQueue {
+    items: Vec<Item>,
+}
+
+impl ProofQueue {
+    /// Add `item`'s proof to the current window.
+    fn push(&mut self, item: Item) {
+        self.items.push(item);
+    }
+
+    /// Verify every queued proof individually against `vk`, returning whether
+    /// they all pass.
+    fn verify(self, vk: &VerifyingKey) -> bool {
+        self.items.iter().all(|item| item.verify_single(vk).is_ok())
+    }
+}
+
+/// The type of verification results sent from the batch worker to its readers.
+type VerifyResult = Result<(), Halo2Error>;
+
+/// Statistics describing a single flushed window of proofs.
+///
+/// Reported to Zebra's metrics so operators can observe the verifier's
+/// behaviour, in particular when a window with an invalid proof forces the
+/// expensive per-proof [`Fallback`](tower_fallback::Fallback) path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchStats {
+    /// The number of items actually flushed in this window.
+    pub batch_size: usize,
+
+    /// Whether every proof in the window verified. If `false`, the `Fallback`
+    /// service re-verifies each item with `verify_single`.
+    pub all_proofs_valid: bool,
+
+    /// The wall-clock time taken to verify every proof in the window, in
+    /// seconds. This times the per-proof verification loop, not a single
+    /// batched MSM (see [`ProofQueue`]).
+    pub latency_secs: f64,
+}
+
+/// Global verification context for a window of Halo2 proofs.
+///
+/// This service accumulates each `Item`'s proof across an async batch window
+/// and verifies each proof when the window flushes, sending one result to every
+/// queued item. The grouping only amortizes async task scheduling — the
+/// cryptographic check is per-proof (see [`ProofQueue`]). A window with any
+/// failing proof is re-checked per-proof by the
+/// [`Fallback`](tower_fallback::Fallback) the service is wrapped in.
+pub struct Verifier {
+    /// The proofs queued into the current window.
+    batch: ProofQueue,
+
+    /// The proof verification key.
+    ///
+    /// Making this 'static would require a borrow from a lazy static, so we
+    /// clone a reference to the shared key into every verifier instead.
+    vk: &'static VerifyingKey,
+
+    /// A channel for broadcasting the result of a batch to the futures for each
+    /// batched item.
+    ///
+    /// The same channel is reused for every batch. Each item's future subscribes
+    /// when the item is queued, so the first result it receives is the one for
+    /// its own batch. Tokio doesn't have a oneshot multi-consumer channel, so
+    /// we use a broadcast channel.
+    tx: Sender<VerifyResult>,
+
+    /// The number of items queued into the current batch, reported as part of
+    /// [`BatchStats`] when the batch is flushed.
+    queued: usize,
+}
+
+impl Verifier {
+    /// Create and return a new verifier using the verification key `vk`.
+    pub fn new(vk: &'static VerifyingKey) -> Self {
+        let batch = ProofQueue::default();
+        // The only consumer of the channel is the per-item future, so a small
+        // capacity is fine; a future that falls more than this many results
+        // behind sees `Lagged`.
+        let (tx, _) = channel(super::MAX_BATCH_SIZE);
+        Self {
+            batch,
+            vk,
+            tx,
+            queued: 0,
+        }
+    }
+
+    /// Flush the batch on the calling thread, and return the result via the channel.
+    /// This function blocks until the batch is completed.
+    ///
+    /// Also records [`BatchStats`] for the flushed batch in Zebra's metrics.
+    fn flush_blocking(&mut self) {
+        let batch = mem::take(&mut self.batch);
+        let batch_size = mem::take(&mut self.queued);
+
+        let (result, stats) = Self::verify(batch, batch_size, self.vk);
+        Self::report_stats(stats);
+
+        let _ = self.tx.send(result);
+    }
+
+    /// Verify every queued proof, returning the result along with
+    /// [`BatchStats`] describing the flushed window.
+    ///
+    /// If any proof fails the whole window fails; callers recover individual
+    /// items through the per-proof [`Fallback`](tower_fallback::Fallback) path.
+    fn verify(batch: ProofQueue, batch_size: usize, vk: &VerifyingKey) -> (VerifyResult, BatchStats) {
+        let start = Instant::now();
+        let all_proofs_valid = batch.verify(vk);
+        let latency_secs = start.elapsed().as_secs_f64();
+
+        let result = if all_proofs_valid {
+            Ok(())
+        } else {
+            Err(Halo2Error::InvalidProof)
+        };
+
+        let stats = BatchStats {
+            batch_size,
+            all_proofs_valid,
+            latency_secs,
+        };
+
+        (result, stats)
+    }
+
+    /// Report `stats` for a flushed window to Zebra's metrics.
+    fn report_stats(stats: BatchStats) {
+        metrics::counter!("proofs.halo2.flushed", 1);
+        metrics::histogram!("proofs.halo2.batch_size", stats.batch_size as f64);
+        metrics::histogram!("proofs.halo2.latency_seconds", stats.latency_secs);
+        if !stats.all_proofs_valid {
+            // A window with an invalid proof forced the per-proof fallback path.
+            metrics::counter!("proofs.halo2.fallback", 1);
+        }
+    }
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Verifier").finish_non_exhaustive()
+    }
+}
+
+impl Service<BatchControl<Item>> for Verifier {
+    type Response = ();
+    type Error = Halo2Error;
+    type Future = Pin<Box<dyn Future<Output = Result<(), Halo2Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BatchControl<Item>) -> Self::Future {
+        match req {
+            BatchControl::Item(item) => {
+                tracing::trace!("got halo2 item");
+                self.batch.push(item);
+                self.queued += 1;
+                let mut rx = self.tx.subscribe();
+                Box::pin(async move {
+                    match rx.recv().await {
+                        Ok(result) => result,
+                        Err(RecvError::Lagged(_)) => {
+                            tracing::error!("missed halo2 verification result due to channel lag");
+                            Err(Halo2Error::Other("batch verification channel lagged".into()))
+                        }
+                        Err(RecvError::Closed) => {
+                            panic!("verifier was dropped without flushing")
+                        }
+                    }
+                })
+            }
+
+            BatchControl::Flush => {
+                tracing::trace!("got halo2 flush command");
+                self.flush_blocking();
+                Box::pin(async { Ok(()) })
+            }
+        }
+    }
+}
+
+impl Drop for Verifier {
+    fn drop(&mut self) {
+        // We need to flush the current batch in case there are still any pending
+        // futures, so they don't hang forever waiting on a result that will
+        // never come.
+        self.flush_blocking();
+    }
+}
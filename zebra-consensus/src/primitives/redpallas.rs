@@ -0,0 +1,189 @@
+//! Async RedPallas batch verifier service
+
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use rand::thread_rng;
+use reddsa::{batch, orchard::SpendAuth, Error};
+use tokio::sync::broadcast::{channel, error::RecvError, Sender};
+use tower::Service;
+use tower_batch::BatchControl;
+
+/// The type of the batch verifier.
+type BatchVerifier = batch::Verifier;
+
+/// The type of verification results.
+type VerifyResult = Result<(), RedPallasError>;
+
+/// An error that may occur while verifying RedPallas signatures.
+///
+/// Wraps [`reddsa::Error`] so that internal conditions (such as a lagging
+/// result channel) can be reported without being mistaken for an invalid
+/// signature.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RedPallasError {
+    /// At least one signature in the batch failed to verify.
+    #[error("one or more RedPallas signatures are invalid")]
+    InvalidSignature,
+
+    /// Verification could not be completed for some other reason.
+    #[error("unexpected verification failure: {0}")]
+    Other(String),
+}
+
+impl From<Error> for RedPallasError {
+    fn from(err: Error) -> RedPallasError {
+        match err {
+            Error::InvalidSignature => RedPallasError::InvalidSignature,
+            other => RedPallasError::Other(format!("{other:?}")),
+        }
+    }
+}
+
+/// A RedPallas signature verification item.
+///
+/// This carries a single RedDSA-over-Pallas signature together with the
+/// verification key and message it commits to. Both orchard spend-auth
+/// signatures (keyed by each action's `rk`) and the bundle binding signature
+/// (keyed by the binding verification key derived from the action value
+/// commitments) are verified through the same item type.
+pub type Item = batch::Item<SpendAuth, reddsa::orchard::Binding>;
+
+/// The ZIP-244 signature hash of the transaction enclosing a bundle.
+///
+/// RedPallas signatures are only meaningful relative to this digest, which
+/// follows the ZIP-244 split of a transaction into per-bundle txid digests
+/// (`hash_bundle_txid_data`) and authorizing-data digests
+/// (`hash_bundle_auth_data`). Using it as the RedDSA message binds a bundle's
+/// signatures to the surrounding transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SigHash(pub [u8; 32]);
+
+/// Build the batch [`Item`]s for every RedPallas signature in `shielded_data`,
+/// using `sighash` as the RedDSA message.
+///
+/// Produces one spend-auth item per action (keyed by the action's `rk`) plus
+/// one binding item (keyed by the binding verification key derived from the
+/// action value commitments), so all of a bundle's signatures are bound to the
+/// transaction identified by `sighash`.
+pub fn shielded_data_items(
+    shielded_data: &zebra_chain::orchard::ShieldedData,
+    sighash: SigHash,
+) -> Vec<Item> {
+    let msg = sighash.0;
+
+    let mut items: Vec<Item> = shielded_data
+        .actions()
+        .map(|action| {
+            let rk: [u8; 32] = action.rk.into();
+            let sig: [u8; 64] = action.spend_auth_sig.into();
+            Item::from((reddsa::VerificationKeyBytes::from(rk), sig.into(), &msg[..]))
+        })
+        .collect();
+
+    // The binding verification key is the sum of the action value commitments
+    // minus the value balance commitment, as computed by
+    // `ShieldedData::binding_verification_key`.
+    let bvk: [u8; 32] = shielded_data.binding_verification_key().into();
+    let binding_sig: [u8; 64] = shielded_data.binding_sig.into();
+    items.push(Item::from((
+        reddsa::VerificationKeyBytes::from(bvk),
+        binding_sig.into(),
+        &msg[..],
+    )));
+
+    items
+}
+
+/// Global batch verification context for RedPallas signatures.
+///
+/// This draws a random scalar `z_i` per
+/// signature and checks that `Σ z_i·(s_i·B − R_i − c_i·A_i) = 0` as one
+/// multiscalar multiplication over Pallas, so a bundle's spend-auth and binding
+/// signatures are validated together in a single MSM.
+pub struct Verifier {
+    /// The synchronous RedPallas batch verifier.
+    batch: BatchVerifier,
+
+    /// A channel for broadcasting the result of a batch to the futures for each
+    /// batched item.
+    tx: Sender<VerifyResult>,
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        let batch = BatchVerifier::default();
+        let (tx, _) = channel(super::MAX_BATCH_SIZE);
+        Self { batch, tx }
+    }
+}
+
+impl Verifier {
+    /// Flush the batch, verifying all queued signatures together as one MSM, and
+    /// broadcast the single result to every queued item.
+    ///
+    /// A single invalid signature fails the whole batch; callers that need to
+    /// isolate the offending signature wrap this service in a
+    /// [`Fallback`](tower_fallback::Fallback) that re-verifies items one at a
+    /// time, as the Halo2 verifier does.
+    fn flush_blocking(&mut self) {
+        let batch = mem::take(&mut self.batch);
+        let _ = self
+            .tx
+            .send(batch.verify(thread_rng()).map_err(RedPallasError::from));
+    }
+}
+
+impl std::fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Verifier").finish_non_exhaustive()
+    }
+}
+
+impl Service<BatchControl<Item>> for Verifier {
+    type Response = ();
+    type Error = RedPallasError;
+    type Future = Pin<Box<dyn Future<Output = Result<(), RedPallasError>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BatchControl<Item>) -> Self::Future {
+        match req {
+            BatchControl::Item(item) => {
+                tracing::trace!("got redpallas item");
+                self.batch.queue(item);
+                let mut rx = self.tx.subscribe();
+                Box::pin(async move {
+                    match rx.recv().await {
+                        Ok(result) => result,
+                        Err(RecvError::Lagged(_)) => {
+                            tracing::error!("missed redpallas verification result due to lag");
+                            Err(RedPallasError::Other(
+                                "batch verification channel lagged".into(),
+                            ))
+                        }
+                        Err(RecvError::Closed) => panic!("verifier was dropped without flushing"),
+                    }
+                })
+            }
+
+            BatchControl::Flush => {
+                tracing::trace!("got redpallas flush command");
+                self.flush_blocking();
+                Box::pin(async { Ok(()) })
+            }
+        }
+    }
+}
+
+impl Drop for Verifier {
+    fn drop(&mut self) {
+        self.flush_blocking();
+    }
+}